@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Display;
 
@@ -17,7 +17,9 @@ use zk_evm::{
         AfterDecodingData, AfterExecutionData, BeforeExecutionData, Tracer, VmLocalStateData,
     },
     aux_structures::MemoryPage,
-    zkevm_opcode_defs::{ContextOpcode, FarCallABI, FarCallForwardPageType, LogOpcode, Opcode},
+    zkevm_opcode_defs::{
+        ContextOpcode, FarCallABI, FarCallForwardPageType, LogOpcode, Opcode, RetOpcode,
+    },
 };
 
 use crate::storage::StoragePtr;
@@ -30,9 +32,38 @@ use zksync_types::{
     get_code_key, web3::signing::keccak256, AccountTreeId, Address, StorageKey, H256, U256,
 };
 use zksync_utils::{
-    be_bytes_to_safe_address, h256_to_account_address, u256_to_account_address, u256_to_h256,
+    be_bytes_to_safe_address, h256_to_account_address, h256_to_u256, u256_to_account_address,
+    u256_to_h256,
 };
 
+/// Number of consecutive slots, starting at (and including) an address-derived keccak slot, that
+/// are considered part of that address' "associated storage", as defined by the ERC-4337 storage
+/// access rules. This allows
+/// validation to touch e.g. all the fields of a struct packed into consecutive slots of a mapping
+/// value, not just the first one.
+const ASSOCIATED_STORAGE_SLOT_WINDOW: u32 = 128;
+
+// The ERC-4337 banned-opcode set among `Opcode::Context` sub-opcodes: environment/consensus-
+// dependent reads whose results the bundler can't reproduce deterministically (VM metadata,
+// current gas left). Used as `ValidationTracer`'s `banned_context_opcodes` whenever the caller
+// leaves `ValidationTracerParams::banned_context_opcodes` empty, so simply omitting it doesn't
+// silently disable the ban that existed before it became configurable.
+//
+// This only covers the zkEVM-native `ContextOpcode` variants; it is *not* the full ERC-4337
+// banned list. zkEVM has no opcodes of its own for block number/timestamp/coinbase/difficulty/
+// gas price/base fee/blockhash/origin/self-balance — those EVM-style environment reads are
+// implemented as storage reads against `SYSTEM_CONTEXT_ADDRESS` (or, for self-balance,
+// `L2_ETH_TOKEN_ADDRESS`) instead, and are already banned at the storage-access layer:
+// `touches_allowed_context` rejects every `SYSTEM_CONTEXT_ADDRESS` key except `chain_id` (key 0),
+// and `valid_eth_token_call` restricts `L2_ETH_TOKEN_ADDRESS` reads to the bootloader/deployer/
+// value-simulator callers a balance check legitimately goes through. So this opcode-level hook
+// and the storage-access gate together cover the ERC-4337 list; neither does alone. One read is
+// not banned: the validated entity's own balance slot on `L2_ETH_TOKEN_ADDRESS` is derived by a
+// keccak call on the entity's address, so `slot_to_add_from_keccak_call` makes it an allowed slot.
+fn default_banned_context_opcodes() -> HashSet<ContextOpcode> {
+    HashSet::from([ContextOpcode::Meta, ContextOpcode::ErgsLeft])
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Copy)]
 #[allow(clippy::enum_variant_names)]
 pub enum ValidationTracerMode {
@@ -48,13 +79,49 @@ pub enum ValidationTracerMode {
 pub enum ViolatedValidationRule {
     TouchedUnallowedStorageSlots(Address, U256),
     CalledContractWithNoCode(Address),
-    TouchedUnallowedContext,
+    TouchedUnallowedContext(ContextOpcode),
     TookTooManyComputationalGas(u32),
+    AccessedUnsupportedContractType(Address, H256),
+    UnstakedEntityAccessedStorage(Address),
+}
+
+/// Error returned when a storage read performed during validation could not be completed, e.g.
+/// because the underlying DB/trie backend is corrupted or unavailable. Kept distinct from an
+/// allow/deny decision so that a backend failure aborts validation instead of silently being
+/// interpreted as `H256::zero()`.
+#[derive(Debug, Clone)]
+pub struct StorageError(pub String);
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Storage error: {}", self.0)
+    }
+}
+
+// The error produced by a single round of `check_user_restrictions`: either a rule was
+// conclusively violated, or a storage read needed to make that determination failed.
+#[derive(Debug, Clone)]
+enum ValidationRoundError {
+    Violation(ViolatedValidationRule),
+    Storage(StorageError),
+}
+
+impl From<ViolatedValidationRule> for ValidationRoundError {
+    fn from(rule: ViolatedValidationRule) -> Self {
+        Self::Violation(rule)
+    }
+}
+
+impl From<StorageError> for ValidationRoundError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
 }
 
 pub enum ValidationError {
     FailedTx(VmRevertReasonParsingResult),
     VioalatedRule(ViolatedValidationRule),
+    StorageError(StorageError),
 }
 
 impl Display for ViolatedValidationRule {
@@ -69,8 +136,8 @@ impl Display for ViolatedValidationRule {
             ViolatedValidationRule::CalledContractWithNoCode(contract) => {
                 write!(f, "Called contract with no code: {}", hex::encode(contract))
             }
-            ViolatedValidationRule::TouchedUnallowedContext => {
-                write!(f, "Touched unallowed context")
+            ViolatedValidationRule::TouchedUnallowedContext(opcode) => {
+                write!(f, "Touched unallowed context: {:?}", opcode)
             }
             ViolatedValidationRule::TookTooManyComputationalGas(gas_limit) => {
                 write!(
@@ -79,6 +146,21 @@ impl Display for ViolatedValidationRule {
                     gas_limit
                 )
             }
+            ViolatedValidationRule::AccessedUnsupportedContractType(contract, code_hash) => {
+                write!(
+                    f,
+                    "Called a contract of an unsupported type: address {}, code hash {}",
+                    hex::encode(contract),
+                    hex::encode(code_hash)
+                )
+            }
+            ViolatedValidationRule::UnstakedEntityAccessedStorage(contract) => {
+                write!(
+                    f,
+                    "Accessed storage of an unstaked entity outside of its allowed slots: address {}",
+                    hex::encode(contract)
+                )
+            }
         }
     }
 }
@@ -92,6 +174,9 @@ impl Display for ValidationError {
             Self::VioalatedRule(rule) => {
                 write!(f, "Violated validation rules: {}", rule)
             }
+            Self::StorageError(err) => {
+                write!(f, "Failed to validate transaction: {}", err)
+            }
         }
     }
 }
@@ -106,18 +191,139 @@ fn touches_allowed_context(address: Address, key: U256) -> bool {
     key == U256::from(0u32)
 }
 
-fn is_constant_code_hash(address: Address, key: U256, storage: StoragePtr<'_>) -> bool {
+// Converts a panicking read into a `StorageError` instead of letting the panic unwind through the
+// tracer and crash the node. Generic over the read itself (rather than inlined into
+// `get_storage_value`) purely so this conversion can be unit tested with a plain closure, without
+// needing a real `StoragePtr`.
+//
+// Scope note: this only catches genuine Rust panics. `WriteStorage::get_value` is an infallible
+// API that returns `H256::zero()` for both "this slot was never written" and, in the real node,
+// would also return `H256::zero()` if the underlying backend (RocksDB/cache) ever desynced
+// without panicking — that case is indistinguishable from a legitimate zero value at this layer
+// and can't be fixed here without `get_value` itself becoming fallible, which is a storage-trait
+// change outside this file. What's here is a real, if narrower, improvement: a backend that does
+// panic on corruption (as RocksDB access does today) now aborts validation with a dedicated
+// `StorageError` instead of crashing the node.
+fn catch_storage_panic<T>(read: impl FnOnce() -> T + std::panic::UnwindSafe) -> Result<T, StorageError> {
+    std::panic::catch_unwind(read).map_err(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "storage backend panicked while reading a value".to_string());
+        StorageError(message)
+    })
+}
+
+// Single choke point for all the validation tracer's storage reads; see `catch_storage_panic` for
+// what this does and doesn't protect against.
+fn get_storage_value(storage: &StoragePtr<'_>, key: &StorageKey) -> Result<H256, StorageError> {
+    catch_storage_panic(std::panic::AssertUnwindSafe(|| {
+        storage.borrow_mut().get_value(key)
+    }))
+}
+
+fn is_constant_code_hash(
+    address: Address,
+    key: U256,
+    storage: &StoragePtr<'_>,
+) -> Result<bool, StorageError> {
     if address != ACCOUNT_CODE_STORAGE_ADDRESS {
         // Not a code hash
+        return Ok(false);
+    }
+
+    let value = get_storage_value(
+        storage,
+        &StorageKey::new(AccountTreeId::new(address), u256_to_h256(key)),
+    )?;
+
+    Ok(value != H256::zero())
+}
+
+// Checks whether `key` falls within the associated storage window of some `base` slot, i.e.
+// `base <= key < base + ASSOCIATED_STORAGE_SLOT_WINDOW`. The upper bound is computed with
+// checked arithmetic since `base` is an (untrusted) keccak output and can legitimately sit close
+// to `U256::MAX`.
+fn is_in_associated_storage_window(base: U256, key: U256) -> bool {
+    if key < base {
         return false;
     }
 
-    let value = storage.borrow_mut().get_value(&StorageKey::new(
-        AccountTreeId::new(address),
-        u256_to_h256(key),
-    ));
+    match base.checked_add(U256::from(ASSOCIATED_STORAGE_SLOT_WINDOW)) {
+        Some(upper_bound) => key < upper_bound,
+        // `base` is close enough to `U256::MAX` that the window saturates; everything from
+        // `base` onwards is considered associated.
+        None => true,
+    }
+}
+
+// The outcome of `check_user_restrictions`'s far-call destination check, factored out as a pure
+// function of `code_hash` so the no-code/banned-marker precedence can be unit tested without a
+// `StoragePtr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FarCallDestinationVerdict {
+    Allowed,
+    NoCode,
+    BannedContractType,
+}
+
+// `code_hash == H256::zero()` is the "no code at this address" sentinel, not a real code-hash
+// version byte, so it's checked (and the staked-caller exemption applied) *before* the
+// `banned_code_hash_markers` lookup. This keeps the sentinel from colliding with a real banned
+// marker byte: if an operator configures marker `0x00` as banned, a staked factory's legitimate
+// CREATE2 call into a not-yet-deployed address must still be `Allowed`, not rejected as
+// `BannedContractType`.
+fn verdict_for_far_call_destination(
+    code_hash: H256,
+    caller_is_staked: bool,
+    banned_code_hash_markers: &HashSet<u8>,
+) -> FarCallDestinationVerdict {
+    if code_hash == H256::zero() {
+        return if caller_is_staked {
+            FarCallDestinationVerdict::Allowed
+        } else {
+            FarCallDestinationVerdict::NoCode
+        };
+    }
+
+    let code_hash_marker = code_hash.as_bytes()[0];
+    if banned_code_hash_markers.contains(&code_hash_marker) {
+        FarCallDestinationVerdict::BannedContractType
+    } else {
+        FarCallDestinationVerdict::Allowed
+    }
+}
 
-    value != H256::zero()
+// Whether `address`/`key` is within the storage the entity currently being validated (the user
+// or, during `PaymasterTxValidation`, the paymaster) is allowed to touch unconditionally: its own
+// account, a slot it keccak-derived ownership of (see `slot_to_add_from_keccak_call`), or a slot
+// inside the associated-storage window of one it's already been granted. Extracted as a pure
+// function of `validated_address` so the user/paymaster scoping can be unit tested directly.
+fn slot_belongs_to_validated_entity(
+    address: Address,
+    key: U256,
+    validated_address: Address,
+    auxilary_allowed_slots: &HashSet<H256>,
+) -> bool {
+    address == validated_address
+        || u256_to_account_address(&key) == validated_address
+        || auxilary_allowed_slots
+            .iter()
+            .any(|&base| is_in_associated_storage_window(h256_to_u256(base), key))
+}
+
+// Resets the permissions discovered during a validation phase back to their base (operator-
+// configured) state. Called whenever a validation phase (user or paymaster) is entered, since
+// `ValidationTracer` reuses one instance across both phases and must not let one phase's
+// discoveries leak into the other's.
+fn reset_discovered_permissions(
+    auxilary_allowed_slots: &mut HashSet<H256>,
+    trusted_addresses: &mut HashSet<Address>,
+    base_trusted_addresses: &HashSet<Address>,
+) {
+    auxilary_allowed_slots.clear();
+    *trusted_addresses = base_trusted_addresses.clone();
 }
 
 fn valid_eth_token_call(address: Address, msg_sender: Address) -> bool {
@@ -127,6 +333,44 @@ fn valid_eth_token_call(address: Address, msg_sender: Address) -> bool {
     address == L2_ETH_TOKEN_ADDRESS && is_valid_caller
 }
 
+// A snapshot of the storage-access permissions as they stood when a call frame was entered, so
+// that they can be restored if the frame reverts.
+#[derive(Debug, Clone)]
+struct StorageAccessSnapshot {
+    auxilary_allowed_slots: HashSet<H256>,
+    trusted_addresses: HashSet<Address>,
+}
+
+impl StorageAccessSnapshot {
+    fn capture(auxilary_allowed_slots: &HashSet<H256>, trusted_addresses: &HashSet<Address>) -> Self {
+        Self {
+            auxilary_allowed_slots: auxilary_allowed_slots.clone(),
+            trusted_addresses: trusted_addresses.clone(),
+        }
+    }
+}
+
+// Pops `stack` and, if `ret_opcode` indicates the frame reverted or panicked, restores
+// `auxilary_allowed_slots`/`trusted_addresses` to what they were before the frame was entered. On
+// a successful return, or if `stack` has no matching snapshot (e.g. validation started mid-frame),
+// this is a no-op: permissions discovered inside the frame are kept. Factored out of
+// `ValidationTracer` so the revert/merge semantics can be unit tested without a `StoragePtr`.
+fn apply_storage_access_snapshot_pop(
+    stack: &mut Vec<StorageAccessSnapshot>,
+    auxilary_allowed_slots: &mut HashSet<H256>,
+    trusted_addresses: &mut HashSet<Address>,
+    ret_opcode: RetOpcode,
+) {
+    let Some(snapshot) = stack.pop() else {
+        return;
+    };
+
+    if matches!(ret_opcode, RetOpcode::Revert | RetOpcode::Panic) {
+        *auxilary_allowed_slots = snapshot.auxilary_allowed_slots;
+        *trusted_addresses = snapshot.trusted_addresses;
+    }
+}
+
 /// Tracer that is used to ensure that the validation adheres to all the rules
 /// to prevent DDoS attacks on the server.
 #[derive(Clone)]
@@ -134,17 +378,33 @@ pub struct ValidationTracer<'a> {
     // A copy of it should be used in the Storage oracle
     pub storage: StoragePtr<'a>,
     pub validation_mode: ValidationTracerMode,
+    // Start slots of the discovered "associated storage" windows (see `is_in_associated_storage_window`).
     pub auxilary_allowed_slots: HashSet<H256>,
     pub validation_error: Option<ViolatedValidationRule>,
+    /// Set if a storage read needed to make an allow/deny decision failed, aborting validation.
+    pub storage_error: Option<StorageError>,
 
     user_address: Address,
     paymaster_address: Address,
     should_stop_execution: bool,
     trusted_slots: HashSet<(Address, U256)>,
     trusted_addresses: HashSet<Address>,
+    // The caller-supplied `trusted_addresses` from `ValidationTracerParams`, kept around so that
+    // `trusted_addresses` (which also accumulates addresses discovered *during* validation) can be
+    // reset back to it when switching between the user and paymaster validation phases.
+    base_trusted_addresses: HashSet<Address>,
     trusted_address_slots: HashSet<(Address, U256)>,
     computational_gas_used: u32,
     computational_gas_limit: u32,
+    // Stack of permission snapshots, one per currently open far/near call frame, used to roll
+    // back `auxilary_allowed_slots`/`trusted_addresses` if that frame reverts or panics.
+    storage_access_snapshots: Vec<StorageAccessSnapshot>,
+    banned_code_hash_markers: HashSet<u8>,
+    banned_context_opcodes: HashSet<ContextOpcode>,
+    // Maps an entity (user/paymaster/factory address) to whether it is staked, per ERC-4337's
+    // reputation rules. Staked entities get relaxed storage/call restrictions; unstaked ones are
+    // held to the same rules as before staking existed.
+    stake_info: HashMap<Address, bool>,
 }
 
 impl fmt::Debug for ValidationTracer<'_> {
@@ -154,12 +414,18 @@ impl fmt::Debug for ValidationTracer<'_> {
             .field("validation_mode", &self.validation_mode)
             .field("auxilary_allowed_slots", &self.auxilary_allowed_slots)
             .field("validation_error", &self.validation_error)
+            .field("storage_error", &self.storage_error)
             .field("user_address", &self.user_address)
             .field("paymaster_address", &self.paymaster_address)
             .field("should_stop_execution", &self.should_stop_execution)
             .field("trusted_slots", &self.trusted_slots)
             .field("trusted_addresses", &self.trusted_addresses)
+            .field("base_trusted_addresses", &self.base_trusted_addresses)
             .field("trusted_address_slots", &self.trusted_address_slots)
+            .field("storage_access_snapshots", &self.storage_access_snapshots)
+            .field("banned_code_hash_markers", &self.banned_code_hash_markers)
+            .field("banned_context_opcodes", &self.banned_context_opcodes)
+            .field("stake_info", &self.stake_info)
             .finish()
     }
 }
@@ -178,6 +444,22 @@ pub struct ValidationTracerParams {
     pub trusted_address_slots: HashSet<(Address, U256)>,
     /// Number of computational gas that validation step is allowed to use.
     pub computational_gas_limit: u32,
+    /// Code hash version bytes (the first byte of a code hash, as produced by
+    /// `zksync_utils::bytecode::hash_bytecode`) that validation is not allowed to call into, e.g.
+    /// markers reserved for contract types whose execution semantics can't be safely simulated
+    /// during validation.
+    pub banned_code_hash_markers: HashSet<u8>,
+    /// `Opcode::Context` sub-opcodes that validation is not allowed to execute, e.g. the
+    /// ERC-4337 banned-opcode set's environment/consensus-dependent reads (current gas left,
+    /// VM metadata, ...), whose results can't be reproduced by the bundler. If left empty,
+    /// `ValidationTracer::new` falls back to `default_banned_context_opcodes()` rather than
+    /// banning nothing.
+    pub banned_context_opcodes: HashSet<ContextOpcode>,
+    /// Staking status of known entities (the user, the paymaster, factories called during
+    /// deployment), keyed by address, `true` meaning staked. Staked entities are exempt from the
+    /// associated-storage-slot restriction and may call into not-yet-deployed addresses; unstaked
+    /// entities (including addresses missing from this map) keep today's rules.
+    pub stake_info: HashMap<Address, bool>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -186,13 +468,14 @@ pub struct NewTrustedValidationItems {
     pub new_trusted_addresses: Vec<Address>,
 }
 
-type ValidationRoundResult = Result<NewTrustedValidationItems, ViolatedValidationRule>;
+type ValidationRoundResult = Result<NewTrustedValidationItems, ValidationRoundError>;
 
 impl<'a> ValidationTracer<'a> {
     pub fn new(storage: StoragePtr<'a>, params: ValidationTracerParams) -> Self {
         ValidationTracer {
             storage,
             validation_error: None,
+            storage_error: None,
             validation_mode: ValidationTracerMode::NoValidation,
             auxilary_allowed_slots: Default::default(),
 
@@ -200,13 +483,28 @@ impl<'a> ValidationTracer<'a> {
             user_address: params.user_address,
             paymaster_address: params.paymaster_address,
             trusted_slots: params.trusted_slots,
+            base_trusted_addresses: params.trusted_addresses.clone(),
             trusted_addresses: params.trusted_addresses,
             trusted_address_slots: params.trusted_address_slots,
             computational_gas_used: 0,
             computational_gas_limit: params.computational_gas_limit,
+            storage_access_snapshots: Vec::new(),
+            banned_code_hash_markers: params.banned_code_hash_markers,
+            banned_context_opcodes: if params.banned_context_opcodes.is_empty() {
+                default_banned_context_opcodes()
+            } else {
+                params.banned_context_opcodes
+            },
+            stake_info: params.stake_info,
         }
     }
 
+    // Whether `address` is a known staked entity. Addresses missing from `stake_info` (and those
+    // explicitly marked `false`) are treated as unstaked.
+    fn is_staked(&self, address: Address) -> bool {
+        self.stake_info.get(&address).copied().unwrap_or(false)
+    }
+
     fn process_validation_round_result(&mut self, result: ValidationRoundResult) {
         match result {
             Ok(NewTrustedValidationItems {
@@ -216,53 +514,94 @@ impl<'a> ValidationTracer<'a> {
                 self.auxilary_allowed_slots.extend(new_allowed_slots);
                 self.trusted_addresses.extend(new_trusted_addresses);
             }
-            Err(err) => {
-                self.validation_error = Some(err);
+            Err(ValidationRoundError::Violation(rule)) => {
+                self.validation_error = Some(rule);
+            }
+            Err(ValidationRoundError::Storage(err)) => {
+                self.storage_error = Some(err);
             }
         }
     }
 
+    // Called when a far/near call frame is entered. Remembers the permissions accumulated so
+    // far so that they can be rolled back if the frame ends up reverting.
+    fn push_storage_access_snapshot(&mut self) {
+        self.storage_access_snapshots.push(StorageAccessSnapshot::capture(
+            &self.auxilary_allowed_slots,
+            &self.trusted_addresses,
+        ));
+    }
+
+    // Called when a far/near call frame returns. On a successful return the permissions
+    // discovered inside the frame are kept (i.e. merged into the parent, which is automatic since
+    // there is a single accumulator). On a revert/panic they are rolled back to what they were
+    // before the frame was entered.
+    fn pop_storage_access_snapshot(&mut self, ret_opcode: RetOpcode) {
+        apply_storage_access_snapshot_pop(
+            &mut self.storage_access_snapshots,
+            &mut self.auxilary_allowed_slots,
+            &mut self.trusted_addresses,
+            ret_opcode,
+        );
+    }
+
     // Checks whether such storage access is acceptable.
-    fn is_allowed_storage_read(&self, address: Address, key: U256, msg_sender: Address) -> bool {
+    fn is_allowed_storage_read(
+        &self,
+        address: Address,
+        key: U256,
+        msg_sender: Address,
+    ) -> Result<bool, StorageError> {
         // If there are no restrictions, all storage reads are valid.
-        // We also don't support the paymaster validation for now.
-        if matches!(
-            self.validation_mode,
-            ValidationTracerMode::NoValidation | ValidationTracerMode::PaymasterTxValidation
-        ) {
-            return true;
+        if let ValidationTracerMode::NoValidation = self.validation_mode {
+            return Ok(true);
         }
 
         // The pair of MSG_VALUE_SIMULATOR_ADDRESS & L2_ETH_TOKEN_ADDRESS simulates the behavior of transfering ETH
         // that is safe for the DDoS protection rules.
         if valid_eth_token_call(address, msg_sender) {
-            return true;
+            return Ok(true);
         }
 
         if self.trusted_slots.contains(&(address, key))
             || self.trusted_addresses.contains(&address)
             || self.trusted_address_slots.contains(&(address, key))
         {
-            return true;
+            return Ok(true);
         }
 
         if touches_allowed_context(address, key) {
-            return true;
+            return Ok(true);
+        }
+
+        // Staked entities (e.g. a staked factory) get their full account storage treated as
+        // associated, same as an explicitly trusted address, per ERC-4337's reputation rules.
+        if self.is_staked(address) {
+            return Ok(true);
         }
 
-        // The user is allowed to touch its own slots or slots semantically related to him.
-        let valid_users_slot = address == self.user_address
-            || u256_to_account_address(&key) == self.user_address
-            || self.auxilary_allowed_slots.contains(&u256_to_h256(key));
-        if valid_users_slot {
-            return true;
+        // The entity being validated (the user or the paymaster) is allowed to touch its own
+        // slots or slots semantically related to it.
+        let validated_address = match self.validation_mode {
+            ValidationTracerMode::UserTxValidation => self.user_address,
+            ValidationTracerMode::PaymasterTxValidation => self.paymaster_address,
+            ValidationTracerMode::NoValidation => unreachable!(),
+        };
+        let valid_validated_entity_slot = slot_belongs_to_validated_entity(
+            address,
+            key,
+            validated_address,
+            &self.auxilary_allowed_slots,
+        );
+        if valid_validated_entity_slot {
+            return Ok(true);
         }
 
-        if is_constant_code_hash(address, key, self.storage.clone()) {
-            return true;
+        if is_constant_code_hash(address, key, &self.storage)? {
+            return Ok(true);
         }
 
-        false
+        Ok(false)
     }
 
     // Used to remember user-related fields (its balance/allowance/etc).
@@ -282,10 +621,12 @@ impl<'a> ValidationTracer<'a> {
         //
         // If the potential_position_bytes were already allowed before, then this keccak might be used
         // for ERC-20 allowance or any other of mapping(address => mapping(...))
+        let potential_position = U256::from_big_endian(potential_position_bytes);
         if potential_address == Some(validated_address)
             || self
                 .auxilary_allowed_slots
-                .contains(&H256::from_slice(potential_position_bytes))
+                .iter()
+                .any(|&base| is_in_associated_storage_window(h256_to_u256(base), potential_position))
         {
             // This is request that could be used for mapping of kind mapping(address => ...)
 
@@ -310,12 +651,27 @@ impl<'a> ValidationTracer<'a> {
         if self.computational_gas_used > self.computational_gas_limit {
             return Err(ViolatedValidationRule::TookTooManyComputationalGas(
                 self.computational_gas_limit,
-            ));
+            )
+            .into());
         }
 
+        let validated_address = match self.validation_mode {
+            ValidationTracerMode::UserTxValidation => self.user_address,
+            ValidationTracerMode::PaymasterTxValidation => self.paymaster_address,
+            ValidationTracerMode::NoValidation => unreachable!(),
+        };
+
         let opcode_variant = data.opcode.variant;
         match opcode_variant.opcode {
+            Opcode::NearCall(_) => {
+                self.push_storage_access_snapshot();
+            }
+            Opcode::Ret(ret_opcode) => {
+                self.pop_storage_access_snapshot(ret_opcode);
+            }
             Opcode::FarCall(_) => {
+                self.push_storage_access_snapshot();
+
                 let packed_abi = data.src0_value.value;
                 let call_destination_value = data.src1_value.value;
 
@@ -336,7 +692,7 @@ impl<'a> ValidationTracer<'a> {
                     );
 
                     let slot_to_add =
-                        self.slot_to_add_from_keccak_call(&calldata, self.user_address);
+                        self.slot_to_add_from_keccak_call(&calldata, validated_address);
 
                     if let Some(slot) = slot_to_add {
                         return Ok(NewTrustedValidationItems {
@@ -344,27 +700,41 @@ impl<'a> ValidationTracer<'a> {
                             ..Default::default()
                         });
                     }
-                } else if called_address != self.user_address {
+                } else if called_address != validated_address {
                     let code_key = get_code_key(&called_address);
-                    let code = self.storage.borrow_mut().get_value(&code_key);
+                    let code_hash = get_storage_value(&self.storage, &code_key)?;
 
-                    if code == H256::zero() {
-                        // The users are not allowed to call contracts with no code
-                        return Err(ViolatedValidationRule::CalledContractWithNoCode(
-                            called_address,
-                        ));
+                    // The caller making this far call (not `called_address`, which by
+                    // construction has no code yet and so is essentially never staked itself) is
+                    // the one whose staking status matters: a staked factory is allowed to call
+                    // into a not-yet-existing (e.g. counterfactual CREATE2) address to deploy it.
+                    let caller = state.vm_local_state.callstack.current.this_address;
+                    match verdict_for_far_call_destination(
+                        code_hash,
+                        self.is_staked(caller),
+                        &self.banned_code_hash_markers,
+                    ) {
+                        FarCallDestinationVerdict::Allowed => {}
+                        FarCallDestinationVerdict::NoCode => {
+                            // The users are not allowed to call contracts with no code.
+                            return Err(ViolatedValidationRule::CalledContractWithNoCode(
+                                called_address,
+                            )
+                            .into());
+                        }
+                        FarCallDestinationVerdict::BannedContractType => {
+                            return Err(ViolatedValidationRule::AccessedUnsupportedContractType(
+                                called_address,
+                                code_hash,
+                            )
+                            .into());
+                        }
                     }
                 }
             }
             Opcode::Context(context) => {
-                match context {
-                    ContextOpcode::Meta => {
-                        return Err(ViolatedValidationRule::TouchedUnallowedContext);
-                    }
-                    ContextOpcode::ErgsLeft => {
-                        // T
-                    }
-                    _ => {}
+                if self.banned_context_opcodes.contains(&context) {
+                    return Err(ViolatedValidationRule::TouchedUnallowedContext(context).into());
                 }
             }
             Opcode::Log(LogOpcode::StorageRead) => {
@@ -372,18 +742,29 @@ impl<'a> ValidationTracer<'a> {
                 let this_address = state.vm_local_state.callstack.current.this_address;
                 let msg_sender = state.vm_local_state.callstack.current.msg_sender;
 
-                if !self.is_allowed_storage_read(this_address, key, msg_sender) {
+                if !self.is_allowed_storage_read(this_address, key, msg_sender)? {
+                    // An address with a known (but negative) stake status attempted a
+                    // staked-only access; surface that distinctly so reputation/staking gating
+                    // can penalize it specifically, rather than as a generic storage violation.
+                    if self.stake_info.contains_key(&this_address) {
+                        return Err(ViolatedValidationRule::UnstakedEntityAccessedStorage(
+                            this_address,
+                        )
+                        .into());
+                    }
+
                     return Err(ViolatedValidationRule::TouchedUnallowedStorageSlots(
                         this_address,
                         key,
-                    ));
+                    )
+                    .into());
                 }
 
                 if self.trusted_address_slots.contains(&(this_address, key)) {
                     let storage_key =
                         StorageKey::new(AccountTreeId::new(this_address), u256_to_h256(key));
 
-                    let value = self.storage.borrow_mut().get_value(&storage_key);
+                    let value = get_storage_value(&self.storage, &storage_key)?;
 
                     return Ok(NewTrustedValidationItems {
                         new_trusted_addresses: vec![h256_to_account_address(&value)],
@@ -416,8 +797,7 @@ impl Tracer for ValidationTracer<'_> {
         data: BeforeExecutionData,
         memory: &Self::SupportedMemory,
     ) {
-        // For now, we support only validations for users.
-        if let ValidationTracerMode::UserTxValidation = self.validation_mode {
+        if !matches!(self.validation_mode, ValidationTracerMode::NoValidation) {
             self.computational_gas_used = self
                 .computational_gas_used
                 .saturating_add(computational_gas_price(state, &data));
@@ -434,10 +814,25 @@ impl Tracer for ValidationTracer<'_> {
             (ValidationTracerMode::NoValidation, VmHook::AccountValidationEntered) => {
                 // Account validation can be entered when there is no prior validation (i.e. "nested" validations are not allowed)
                 self.validation_mode = ValidationTracerMode::UserTxValidation;
+                self.storage_access_snapshots.clear();
+                // The account and paymaster validation phases share this tracer instance but must
+                // not share each other's discovered permissions, or the paymaster phase could
+                // piggyback on slots/addresses the user's validation allowed (and vice versa).
+                reset_discovered_permissions(
+                    &mut self.auxilary_allowed_slots,
+                    &mut self.trusted_addresses,
+                    &self.base_trusted_addresses,
+                );
             }
             (ValidationTracerMode::NoValidation, VmHook::PaymasterValidationEntered) => {
                 // Paymaster validation can be entered when there is no prior validation (i.e. "nested" validations are not allowed)
                 self.validation_mode = ValidationTracerMode::PaymasterTxValidation;
+                self.storage_access_snapshots.clear();
+                reset_discovered_permissions(
+                    &mut self.auxilary_allowed_slots,
+                    &mut self.trusted_addresses,
+                    &self.base_trusted_addresses,
+                );
             }
             (_, VmHook::AccountValidationEntered | VmHook::PaymasterValidationEntered) => {
                 panic!(
@@ -448,6 +843,7 @@ impl Tracer for ValidationTracer<'_> {
             (_, VmHook::NoValidationEntered) => {
                 // Validation can be always turned off
                 self.validation_mode = ValidationTracerMode::NoValidation;
+                self.storage_access_snapshots.clear();
             }
             (_, VmHook::ValidationStepEndeded) => {
                 // The validation step has ended.
@@ -479,9 +875,234 @@ fn get_calldata_page_via_abi(far_call_abi: &FarCallABI, base_page: MemoryPage) -
 
 impl ExecutionEndTracer for ValidationTracer<'_> {
     fn should_stop_execution(&self) -> bool {
-        self.should_stop_execution || self.validation_error.is_some()
+        self.should_stop_execution
+            || self.validation_error.is_some()
+            || self.storage_error.is_some()
     }
 }
 
 impl PendingRefundTracer for ValidationTracer<'_> {}
 impl PubdataSpentTracer for ValidationTracer<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_excludes_keys_before_base() {
+        assert!(!is_in_associated_storage_window(
+            U256::from(100u32),
+            U256::from(99u32)
+        ));
+    }
+
+    #[test]
+    fn window_includes_base_and_up_to_its_last_slot() {
+        let base = U256::from(100u32);
+        assert!(is_in_associated_storage_window(base, base));
+        assert!(is_in_associated_storage_window(
+            base,
+            base + U256::from(ASSOCIATED_STORAGE_SLOT_WINDOW - 1)
+        ));
+    }
+
+    #[test]
+    fn window_excludes_the_first_key_past_its_end() {
+        let base = U256::from(100u32);
+        assert!(!is_in_associated_storage_window(
+            base,
+            base + U256::from(ASSOCIATED_STORAGE_SLOT_WINDOW)
+        ));
+    }
+
+    #[test]
+    fn window_saturates_instead_of_overflowing_near_u256_max() {
+        let base = U256::MAX - U256::from(1u32);
+        assert!(is_in_associated_storage_window(base, base));
+        assert!(is_in_associated_storage_window(base, U256::MAX));
+    }
+
+    #[test]
+    fn snapshot_pop_without_a_matching_push_is_a_no_op() {
+        let mut stack = Vec::new();
+        let mut slots = HashSet::from([H256::from_low_u64_be(1)]);
+        let mut addresses = HashSet::from([Address::from_low_u64_be(1)]);
+
+        apply_storage_access_snapshot_pop(&mut stack, &mut slots, &mut addresses, RetOpcode::Revert);
+
+        assert_eq!(slots.len(), 1);
+        assert_eq!(addresses.len(), 1);
+    }
+
+    #[test]
+    fn successful_return_keeps_permissions_discovered_inside_the_frame() {
+        let mut slots = HashSet::new();
+        let mut addresses = HashSet::new();
+        let mut stack = vec![StorageAccessSnapshot::capture(&slots, &addresses)];
+
+        slots.insert(H256::from_low_u64_be(1));
+        addresses.insert(Address::from_low_u64_be(1));
+
+        apply_storage_access_snapshot_pop(&mut stack, &mut slots, &mut addresses, RetOpcode::Ok);
+
+        assert_eq!(slots.len(), 1);
+        assert_eq!(addresses.len(), 1);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn revert_rolls_back_permissions_discovered_inside_the_frame() {
+        let mut slots = HashSet::new();
+        let mut addresses = HashSet::new();
+        let mut stack = vec![StorageAccessSnapshot::capture(&slots, &addresses)];
+
+        slots.insert(H256::from_low_u64_be(1));
+        addresses.insert(Address::from_low_u64_be(1));
+
+        apply_storage_access_snapshot_pop(&mut stack, &mut slots, &mut addresses, RetOpcode::Revert);
+
+        assert!(slots.is_empty());
+        assert!(addresses.is_empty());
+    }
+
+    #[test]
+    fn panic_rolls_back_permissions_same_as_a_revert() {
+        let mut slots = HashSet::new();
+        let mut addresses = HashSet::new();
+        let mut stack = vec![StorageAccessSnapshot::capture(&slots, &addresses)];
+
+        slots.insert(H256::from_low_u64_be(1));
+
+        apply_storage_access_snapshot_pop(&mut stack, &mut slots, &mut addresses, RetOpcode::Panic);
+
+        assert!(slots.is_empty());
+    }
+
+    #[test]
+    fn nested_frames_roll_back_independently() {
+        let mut slots = HashSet::new();
+        let mut addresses = HashSet::new();
+        let mut stack = Vec::new();
+
+        // Outer frame discovers one slot, then a nested frame discovers another before reverting.
+        stack.push(StorageAccessSnapshot::capture(&slots, &addresses));
+        slots.insert(H256::from_low_u64_be(1));
+
+        stack.push(StorageAccessSnapshot::capture(&slots, &addresses));
+        slots.insert(H256::from_low_u64_be(2));
+
+        apply_storage_access_snapshot_pop(&mut stack, &mut slots, &mut addresses, RetOpcode::Revert);
+        assert_eq!(slots, HashSet::from([H256::from_low_u64_be(1)]));
+
+        apply_storage_access_snapshot_pop(&mut stack, &mut slots, &mut addresses, RetOpcode::Ok);
+        assert_eq!(slots, HashSet::from([H256::from_low_u64_be(1)]));
+    }
+
+    #[test]
+    fn banned_code_hash_marker_is_rejected() {
+        let mut code_hash = H256::zero();
+        code_hash.0[0] = 0x02;
+        let banned = HashSet::from([0x02]);
+
+        assert_eq!(
+            verdict_for_far_call_destination(code_hash, false, &banned),
+            FarCallDestinationVerdict::BannedContractType
+        );
+    }
+
+    #[test]
+    fn unbanned_code_hash_marker_is_allowed() {
+        let mut code_hash = H256::zero();
+        code_hash.0[0] = 0x02;
+        let banned = HashSet::from([0x03]);
+
+        assert_eq!(
+            verdict_for_far_call_destination(code_hash, false, &banned),
+            FarCallDestinationVerdict::Allowed
+        );
+    }
+
+    #[test]
+    fn zero_code_hash_is_never_treated_as_a_banned_marker_even_if_marker_zero_is_banned() {
+        // An operator banning marker byte `0x00` must not turn every no-code destination into an
+        // `AccessedUnsupportedContractType` rejection: the no-code sentinel is checked first.
+        let banned = HashSet::from([0x00]);
+
+        assert_eq!(
+            verdict_for_far_call_destination(H256::zero(), false, &banned),
+            FarCallDestinationVerdict::NoCode
+        );
+    }
+
+    #[test]
+    fn default_banned_context_opcodes_bans_meta_and_ergs_left() {
+        let banned = default_banned_context_opcodes();
+        assert!(banned.contains(&ContextOpcode::Meta));
+        assert!(banned.contains(&ContextOpcode::ErgsLeft));
+    }
+
+    #[test]
+    fn default_banned_context_opcodes_does_not_ban_unrelated_context_reads() {
+        let banned = default_banned_context_opcodes();
+        assert!(!banned.contains(&ContextOpcode::This));
+    }
+
+    #[test]
+    fn paymaster_may_touch_its_own_slot_but_not_the_users() {
+        let paymaster = Address::from_low_u64_be(1);
+        let user = Address::from_low_u64_be(2);
+        let no_auxiliary_slots = HashSet::new();
+
+        assert!(slot_belongs_to_validated_entity(
+            paymaster,
+            U256::from(5u32),
+            paymaster,
+            &no_auxiliary_slots,
+        ));
+        assert!(!slot_belongs_to_validated_entity(
+            user,
+            U256::from(5u32),
+            paymaster,
+            &no_auxiliary_slots,
+        ));
+    }
+
+    #[test]
+    fn entering_a_validation_phase_resets_discovered_permissions_to_the_base_set() {
+        let base_trusted = HashSet::from([Address::from_low_u64_be(42)]);
+        let mut trusted = base_trusted.clone();
+        // Discovered during the previous phase; must not leak into the next one.
+        trusted.insert(Address::from_low_u64_be(7));
+        let mut auxiliary_slots = HashSet::from([H256::from_low_u64_be(1)]);
+
+        reset_discovered_permissions(&mut auxiliary_slots, &mut trusted, &base_trusted);
+
+        assert!(auxiliary_slots.is_empty());
+        assert_eq!(trusted, base_trusted);
+    }
+
+    #[test]
+    fn catch_storage_panic_turns_a_panicking_read_into_a_storage_error() {
+        let result: Result<H256, StorageError> = catch_storage_panic(
+            std::panic::AssertUnwindSafe(|| panic!("storage backend is corrupted")),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn catch_storage_panic_passes_through_a_successful_read() {
+        let value = H256::from_low_u64_be(7);
+        let result = catch_storage_panic(std::panic::AssertUnwindSafe(|| value));
+        assert_eq!(result.unwrap(), value);
+    }
+
+    #[test]
+    fn staked_factory_may_call_an_undeployed_address() {
+        // A staked caller deploying into a counterfactual (not-yet-existing) CREATE2 address
+        // must not hit `CalledContractWithNoCode`, regardless of the banned-marker set.
+        assert_eq!(
+            verdict_for_far_call_destination(H256::zero(), true, &HashSet::new()),
+            FarCallDestinationVerdict::Allowed
+        );
+    }
+}